@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+
+/// Makes Ctrl-C set the interruption flag instead of terminating the program.
+/// A second Ctrl-C terminates the program
+#[cfg(feature = "interrupt")]
+pub fn install_handler() {
+    let result = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+    });
+
+    if let Err(err) = result {
+        println!("Warning: cannot handle Ctrl-C: {}", err);
+    }
+}
+
+
+/// Ctrl-C handling is disabled
+#[cfg(not(feature = "interrupt"))]
+pub fn install_handler() {}
+
+
+pub fn is_interrupted() -> bool {
+    #[cfg(test)]
+    if simulated_interrupt() {
+        return true;
+    }
+
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+
+#[cfg(test)]
+thread_local! {
+    /// Checks left before a simulated Ctrl-C. It is per thread,
+    /// so that other tests running in parallel are not interrupted
+    static CHECKS_LEFT: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+}
+
+
+/// Makes [is_interrupted] return true on this thread after the given number of checks.
+/// None cancels the simulation
+#[cfg(test)]
+pub fn simulate_interrupt(after_checks: Option<usize>) {
+    CHECKS_LEFT.with(|left| left.set(after_checks));
+}
+
+
+#[cfg(test)]
+fn simulated_interrupt() -> bool {
+    CHECKS_LEFT.with(|left| match left.get() {
+        Some(0) => true,
+        Some(checks) => {
+            left.set(Some(checks - 1));
+            false
+        }
+        None => false,
+    })
+}
@@ -7,9 +7,10 @@ extern crate kurbo;
 
 mod svgps;
 mod svgcom;
+mod interrupt;
 
 use svgps::{
-    generate_from_svg, render_to_svg
+    generate_from_svg, render_to_svg, validate_svgcom, export_gcode, repair_svgcom
 };
 
 
@@ -36,7 +37,16 @@ enum ArgCommand {
     Generate(GenerateArgs),
 
     /// Render svgcom file (useful for previewing before submitting to plotters)
-    Render(RenderArgs)
+    Render(RenderArgs),
+
+    /// Check svgcom file for problems
+    Validate(ValidateArgs),
+
+    /// Convert svgcom file to G-code
+    Gcode(GcodeArgs),
+
+    /// Fix common problems of svgcom file (e.g. after manual editing)
+    Repair(RepairArgs),
 }
 
 
@@ -67,6 +77,63 @@ pub struct GenerateArgs {
     /// Convert only stroked paths
     #[arg(short = 's', long)]
     onlystroked: bool,
+
+    /// Plotter resolution (steps per pixel)
+    #[arg(long)]
+    steps_per_unit: Option<f64>,
+
+    /// Round the output coordinates to the plotter steps (to whole pixels without STEPS_PER_UNIT)
+    #[arg(long, value_enum, value_name = "MODE")]
+    rounding: Option<svgcom::Rounding>,
+
+    /// Derive the curve flattening tolerance from the plotter resolution (half a step)
+    /// instead of using PRECISION
+    #[arg(long, requires = "steps_per_unit")]
+    flatten_tolerance_auto: bool,
+
+    /// Convert curves into lines (with the flattening tolerance of autocut)
+    #[arg(long)]
+    flatten: bool,
+
+    /// Snap lines that deviate from horizontal or vertical by at most this angle (in degrees)
+    #[arg(long, value_name = "DEGREES")]
+    quantize_angle: Option<f64>,
+
+    /// Simplify runs of lines with the Douglas-Peucker algorithm (tolerance in pixels)
+    #[arg(long, value_name = "TOLERANCE")]
+    simplify: Option<f64>,
+
+    /// Never remove vertices with a turn angle greater than CORNER_ANGLE when simplifying
+    #[arg(long, requires = "simplify")]
+    simplify_preserve_corners: bool,
+
+    /// Minimal turn angle (in degrees) of a vertex for it to be a corner
+    #[arg(long, default_value_t = 45.0)]
+    corner_angle: f64,
+
+    /// Fit runs of lines produced by flattening with cubic curves
+    /// deviating by at most this distance (in pixels)
+    #[arg(long, value_name = "DISTANCE", requires = "flatten")]
+    max_curve_error: Option<f64>,
+
+    /// Reverse every other line in runs of parallel lines (e.g. hatching)
+    /// so that the pen snakes back and forth
+    #[arg(long)]
+    reflow: bool,
+
+    /// Store pen pressure of every subpath (derived from the stroke opacity)
+    #[arg(long)]
+    pen_pressure: bool,
+
+    /// Split the image into a grid of tiles written to separate files named OUTPUT-ROW-COL.
+    /// Coordinates are relative to the tile
+    #[arg(long, num_args = 2, value_names = ["ROWS", "COLS"])]
+    split_by_bbox: Option<Vec<u32>>,
+
+    /// Write per-subpath statistics to a CSV file.
+    /// The statistics describe the whole image, before it is split into tiles
+    #[arg(long)]
+    csv: Option<PathBuf>,
 }
 
 
@@ -92,6 +159,45 @@ pub struct RenderArgs {
 }
 
 
+#[derive(clap::Args)]
+pub struct ValidateArgs {
+    /// SVG commands file (.svgcom)
+    input: PathBuf,
+}
+
+
+#[derive(clap::Args)]
+pub struct GcodeArgs {
+    /// SVG commands file (.svgcom)
+    input: PathBuf,
+
+    /// G-code file (.gcode)
+    output: PathBuf,
+
+    /// Precision of curve flattening (in pixels)
+    #[arg(short = 'e', long, default_value_t = 0.25)]
+    precision: f64,
+
+    /// Command that lowers the pen
+    #[arg(long, default_value = "G1 Z0")]
+    pen_down_cmd: String,
+
+    /// Command that raises the pen
+    #[arg(long, default_value = "G0 Z1")]
+    pen_up_cmd: String,
+}
+
+
+#[derive(clap::Args)]
+pub struct RepairArgs {
+    /// SVG commands file (.svgcom)
+    input: PathBuf,
+
+    /// Repaired SVG commands file (.svgcom), INPUT is overwritten by default
+    output: Option<PathBuf>,
+}
+
+
 pub type Error = String;
 
 
@@ -103,6 +209,9 @@ fn main() {
     let result: Result<(), Error> = match args.command {
         ArgCommand::Generate(args) => generate_from_svg(args),
         ArgCommand::Render(args) => render_to_svg(args),
+        ArgCommand::Validate(args) => validate_svgcom(args),
+        ArgCommand::Gcode(args) => export_gcode(args),
+        ArgCommand::Repair(args) => repair_svgcom(args),
     };
 
     if let Err(message) = result {
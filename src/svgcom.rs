@@ -10,10 +10,22 @@ pub struct ImageSize {
 }
 
 
+/// Rounding mode of coordinate quantization
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Rounding {
+    Nearest,
+    Floor,
+    Ceil,
+}
+
+
 /// Final .svgcom representation
 pub struct SvgCom {
     pub view_size: ImageSize,
     pub commands: kurbo::BezPath,
+
+    /// Pen pressure (0.0-1.0) of every subpath, i.e. one value per MoveTo
+    pub pressures: Option<Vec<f64>>,
 }
 
 
@@ -26,16 +38,34 @@ impl ImageSize {
 }
 
 
+impl Rounding {
+    pub fn apply(self, value: f64) -> f64 {
+        match self {
+            Rounding::Nearest => value.round(),
+            Rounding::Floor => value.floor(),
+            Rounding::Ceil => value.ceil(),
+        }
+    }
+
+
+    /// Rounds the value to a whole number of steps, `steps` per unit
+    pub fn quantize(self, value: f64, steps: f64) -> f64 {
+        self.apply(value * steps) / steps
+    }
+}
+
+
 impl SvgCom {
     pub fn new(width: f64, height: f64) -> Self {
         Self {
             view_size: ImageSize::new(width.ceil() as u32, height.ceil() as u32),
-            commands: kurbo::BezPath::new()
+            commands: kurbo::BezPath::new(),
+            pressures: None,
         }
     }
 
 
-    pub fn read_from_paths(&mut self, paths: &Vec<Path>) {
+    pub fn read_from_paths(&mut self, paths: &[Path]) {
         for path in paths {
             self.read_from_path(path);
         }
@@ -43,7 +73,19 @@ impl SvgCom {
 
 
     fn read_from_path(&mut self, path: &Path) {
-        self.commands.extend(kurbo::BezPath::from_path_segments(path.segments.clone().into_iter()));
+        let bezpath = kurbo::BezPath::from_path_segments(path.segments.clone().into_iter());
+
+        if let Some(pressures) = &mut self.pressures {
+            let pressure = path.source.get_pressure();
+
+            let subpaths_count = bezpath.iter()
+                .filter(|cmd| matches!(cmd, kurbo::PathEl::MoveTo(_)))
+                .count();
+
+            pressures.extend(std::iter::repeat_n(pressure, subpaths_count));
+        }
+
+        self.commands.extend(bezpath);
     }
 
 
@@ -67,6 +109,58 @@ impl SvgCom {
 
         me.read_svgcom_data(commands, coords)?;
 
+        if let Some(line) = lines.next() {
+            me.pressures = Self::parse_svgcom_pressures(line);
+        }
+
+        Ok(me)
+    }
+
+
+    /// Reads a possibly broken .svgcom file: the header counts are ignored,
+    /// unknown characters and unparsable coordinates are skipped
+    /// and the commands lacking coordinates are dropped
+    pub fn from_svgcom_str_lenient(source: &str) -> Result<Self, Error> {
+        let mut lines = source.lines();
+
+        let size = lines.next()
+            .unwrap_or("")
+            .split_whitespace()
+            .take(2)
+            .map(|x| x.parse::<u32>()
+                .map_err(|err| format!("Uint32 parsing error: {}", err)))
+            .collect::<Result<Vec<u32>, Error>>()?;
+
+        if size.len() != 2 {
+            return Err("Expected the image size: WIDTH, HEIGHT".to_string());
+        }
+
+        let mut commands = Self::parse_svgcom_commands(lines.next().unwrap_or(""));
+        commands.retain(|cmd| matches!(cmd, 'M' | 'L' | 'C'));
+
+        let mut coords = lines.next()
+            .unwrap_or("")
+            .split_whitespace()
+            .filter_map(|x| x.parse::<f64>().ok())
+            .collect::<Vec<f64>>();
+
+        let mut used_coords = 0;
+        let usable_commands = commands.iter()
+            .take_while(|cmd| {
+                used_coords += if **cmd == 'C' { 6 } else { 2 };
+                used_coords <= coords.len()
+            })
+            .count();
+
+        commands.truncate(usable_commands);
+        coords.truncate(commands.iter().map(|cmd| if *cmd == 'C' { 6 } else { 2 }).sum());
+
+        let mut me = Self::new(size[0] as f64, size[1] as f64);
+
+        me.read_svgcom_data(commands, coords)?;
+
+        me.pressures = Self::parse_svgcom_pressures(lines.next().unwrap_or(""));
+
         Ok(me)
     }
 
@@ -99,6 +193,13 @@ impl SvgCom {
             .collect::<Result<Vec<f64>, Error>>()
     }
 
+    /// Pressures are optional, so lines without the "P" prefix are ignored
+    /// A line that is not a well-formed pressure list is garbage and yields no pressures
+    fn parse_svgcom_pressures(line: &str) -> Option<Vec<f64>> {
+        line.strip_prefix("P ")
+            .and_then(|line| Self::parse_svgcom_coords(line).ok())
+    }
+
     fn validate_svgcom_metrics(metrics: &Vec<u32>, commands: &Vec<char>, coords: &Vec<f64>) -> Result<(), Error> {
         if commands.len() != metrics[2] as usize || coords.len() != metrics[3] as usize {
             return Err("Data length does not match the header information".to_string());
@@ -107,24 +208,221 @@ impl SvgCom {
         Ok(())
     }
 
+    /// Commands are pushed as is, so that a missing leading MoveTo is reported by [Self::validate]
     fn read_svgcom_data(&mut self, commands: Vec<char>, coords: Vec<f64>) -> Result<(), Error> {
         let mut coords_iter = coords.iter();
         let mut get_point = || {
-            kurbo::Point::new(*coords_iter.next().unwrap(), *coords_iter.next().unwrap())
+            match (coords_iter.next(), coords_iter.next()) {
+                (Some(x), Some(y)) => Ok(kurbo::Point::new(*x, *y)),
+                _ => Err("Not enough coordinates for the commands".to_string()),
+            }
         };
 
         for cmd in commands {
-            match cmd {
-                'M' => self.commands.move_to(get_point()),
-                'L' => self.commands.line_to(get_point()),
-                'C' => self.commands.curve_to(get_point(), get_point(), get_point()),
+            let element = match cmd {
+                'M' => kurbo::PathEl::MoveTo(get_point()?),
+                'L' => kurbo::PathEl::LineTo(get_point()?),
+                'C' => kurbo::PathEl::CurveTo(get_point()?, get_point()?, get_point()?),
                 c => return Err(format!("Invalid command: {}", c)),
-            }
+            };
+
+            self.commands.push(element);
+        }
+
+        if coords_iter.next().is_some() {
+            return Err("Too many coordinates for the commands".to_string());
         }
 
         Ok(())
     }
 
+
+    /// Drops commands that cannot be written, non-finite points, zero-length segments
+    /// and empty subpaths. Drawing commands before the first MoveTo only move the pen.
+    /// Pressures are kept for the remaining subpaths (and dropped if they do not match the subpaths).
+    ///
+    /// Repairing a repaired path changes nothing
+    pub fn repair(&mut self) {
+        let pressures = self.pressures.take()
+            .filter(|pressures| pressures.len() == self.subpaths_count());
+
+        let mut repaired = kurbo::BezPath::new();
+        let mut repaired_pressures = Vec::<f64>::new();
+
+        let mut current_point: Option<kurbo::Point> = None;
+        let mut pending_move: Option<kurbo::Point> = None;
+        let mut pending_pressure = 1.;
+        let mut subpath_index = 0;
+
+        for cmd in self.commands.iter() {
+            let points = match cmd {
+                kurbo::PathEl::MoveTo(p) => vec![p],
+                kurbo::PathEl::LineTo(p) => vec![p],
+                kurbo::PathEl::CurveTo(p1, p2, p3) => vec![p1, p2, p3],
+                _ => continue,
+            };
+
+            if points.iter().any(|p| !p.is_finite()) {
+                continue;
+            }
+
+            let end = *points.last().unwrap();
+
+            if let kurbo::PathEl::MoveTo(_) = cmd {
+                pending_pressure = pressures.as_ref()
+                    .map_or(1., |pressures| pressures[subpath_index].clamp(0., 1.));
+                subpath_index += 1;
+            }
+
+            let start = match current_point {
+                Some(start) if !matches!(cmd, kurbo::PathEl::MoveTo(_)) => start,
+                _ => {
+                    current_point = Some(end);
+                    pending_move = Some(end);
+                    continue;
+                }
+            };
+
+            if points.iter().all(|p| *p == start) {
+                continue;
+            }
+
+            if let Some(p) = pending_move.take() {
+                repaired.move_to(p);
+                repaired_pressures.push(pending_pressure);
+            }
+
+            repaired.push(cmd);
+            current_point = Some(end);
+        }
+
+        self.commands = repaired;
+        self.pressures = pressures.map(|_| repaired_pressures);
+    }
+
+
+    /// Rounds all the coordinates to a whole number of steps, `steps_per_unit` per unit
+    pub fn quantize(&mut self, steps_per_unit: f64, rounding: Rounding) {
+        let quantize_point = |p: kurbo::Point| kurbo::Point::new(
+            rounding.quantize(p.x, steps_per_unit),
+            rounding.quantize(p.y, steps_per_unit)
+        );
+
+        self.commands = self.commands.iter()
+            .map(|cmd| match cmd {
+                kurbo::PathEl::MoveTo(p) => kurbo::PathEl::MoveTo(quantize_point(p)),
+                kurbo::PathEl::LineTo(p) => kurbo::PathEl::LineTo(quantize_point(p)),
+                kurbo::PathEl::QuadTo(p1, p2) => kurbo::PathEl::QuadTo(quantize_point(p1), quantize_point(p2)),
+                kurbo::PathEl::CurveTo(p1, p2, p3) =>
+                    kurbo::PathEl::CurveTo(quantize_point(p1), quantize_point(p2), quantize_point(p3)),
+                kurbo::PathEl::ClosePath => kurbo::PathEl::ClosePath,
+            })
+            .collect::<kurbo::BezPath>();
+    }
+
+
+    pub fn subpaths_count(&self) -> usize {
+        self.commands.iter()
+            .filter(|cmd| matches!(cmd, kurbo::PathEl::MoveTo(_)))
+            .count()
+    }
+
+
+    /// Returns all the detected problems: commands that cannot be written,
+    /// a path not starting with MoveTo, non-finite and out-of-bounds points
+    pub fn validate(&self) -> Result<(), Vec<Error>> {
+        use kurbo::ParamCurveExtrema;
+
+        let mut problems = Vec::<Error>::new();
+
+        let mut current_point: Option<kurbo::Point> = None;
+        let mut subpath_start: Option<kurbo::Point> = None;
+
+        for (index, cmd) in self.commands.iter().enumerate() {
+            let points = match cmd {
+                kurbo::PathEl::MoveTo(p) => vec![p],
+                kurbo::PathEl::LineTo(p) => vec![p],
+                kurbo::PathEl::CurveTo(p1, p2, p3) => vec![p1, p2, p3],
+                kurbo::PathEl::ClosePath => {
+                    current_point = subpath_start;
+                    continue;
+                }
+                _ => {
+                    problems.push(format!("Command {}: unsupported command {:?}", index, cmd));
+                    continue;
+                }
+            };
+
+            if index == 0 && !matches!(cmd, kurbo::PathEl::MoveTo(_)) {
+                problems.push(format!("Command {}: the path does not start with MoveTo", index));
+            }
+
+            for p in &points {
+                if !p.is_finite() {
+                    problems.push(format!("Command {}: non-finite point ({}, {})", index, p.x, p.y));
+                }
+            }
+
+            let finite = |p: &kurbo::Point| p.is_finite();
+
+            // Control points of a curve may lie outside, as long as the curve itself does not
+            match (cmd, current_point.filter(finite)) {
+                (kurbo::PathEl::CurveTo(p1, p2, p3), Some(p0)) if points.iter().all(finite) => {
+                    let bbox = kurbo::CubicBez::new(p0, p1, p2, p3).bounding_box();
+
+                    if !self.contains_point(bbox.origin()) || !self.contains_point(kurbo::Point::new(bbox.x1, bbox.y1)) {
+                        problems.push(format!("Command {}: curve is out of bounds", index));
+                    }
+                }
+
+                _ => {
+                    for p in points.iter().filter(|p| finite(p) && !self.contains_point(**p)) {
+                        problems.push(format!("Command {}: point ({}, {}) is out of bounds", index, p.x, p.y));
+                    }
+                }
+            }
+
+            if let kurbo::PathEl::MoveTo(p) = cmd {
+                subpath_start = Some(p);
+            }
+
+            current_point = points.last().copied();
+        }
+
+        if let Some(pressures) = &self.pressures {
+            if pressures.len() != self.subpaths_count() {
+                problems.push(format!("Expected {} pressure values, got {}", self.subpaths_count(), pressures.len()));
+            }
+
+            for (index, pressure) in pressures.iter().enumerate() {
+                if !(0. ..=1.).contains(pressure) {
+                    problems.push(format!("Subpath {}: pressure {} is out of range 0..1", index, pressure));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+
+    fn contains_point(&self, p: kurbo::Point) -> bool {
+        p.x >= 0. && p.x <= self.view_size.width as f64
+            && p.y >= 0. && p.y <= self.view_size.height as f64
+    }
+
+
+    /// Number of the written commands (ClosePath is not written)
+    pub fn commands_count(&self) -> usize {
+        self.commands.iter()
+            .filter(|cmd| !matches!(cmd, kurbo::PathEl::ClosePath))
+            .count()
+    }
+
+
     pub fn points_count(&self) -> usize {
         let mut npoints = 0;
 
@@ -133,6 +431,7 @@ impl SvgCom {
                 kurbo::PathEl::MoveTo(_) => 1,
                 kurbo::PathEl::LineTo(_) => 1,
                 kurbo::PathEl::CurveTo(_, _, _) => 3,
+                kurbo::PathEl::ClosePath => 0,
                 _ => panic!("unexpected command"),
             };
         }
@@ -155,6 +454,7 @@ impl SvgCom {
         self.format_svgcom_metrics(f)?;
         self.format_svgcom_commands(f)?;
         self.format_svgcom_points(f)?;
+        self.format_svgcom_pressures(f)?;
         Ok(())
     }
 
@@ -163,7 +463,7 @@ impl SvgCom {
         writeln!(f, "{} {} {} {}",
             self.view_size.width,
             self.view_size.height,
-            self.commands.elements().len(),
+            self.commands_count(),
             self.coordinates_count()
         )
     }
@@ -186,7 +486,10 @@ impl SvgCom {
 
 
     fn format_svgcom_points(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for (i, cmd) in self.commands.iter().enumerate() {
+        let written_commands = self.commands.iter()
+            .filter(|cmd| !matches!(cmd, kurbo::PathEl::ClosePath));
+
+        for (i, cmd) in written_commands.enumerate() {
             if i != 0 { write!(f, " ")?; }
 
             match cmd {
@@ -207,6 +510,21 @@ impl SvgCom {
         
         Ok(())
     }
+
+
+    fn format_svgcom_pressures(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(pressures) = &self.pressures {
+            write!(f, "P")?;
+
+            for pressure in pressures {
+                write!(f, " {}", pressure)?;
+            }
+
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
 }
 
 
@@ -214,4 +532,108 @@ impl std::fmt::Display for SvgCom {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.format_svgcom(f)
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn validate_finds_out_of_bounds_point() {
+        let svgcom = SvgCom::from_svgcom_str("10 10 2 4\nML\n1 1 20 5\n").unwrap();
+
+        let problems = svgcom.validate().unwrap_err();
+
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("out of bounds"));
+    }
+
+
+    #[test]
+    fn validate_checks_curves_not_control_points() {
+        let svgcom = SvgCom::from_svgcom_str("100 100 2 8\nMC\n10 50 10 -10 90 -10 90 50\n").unwrap();
+        assert_eq!(svgcom.validate(), Ok(()));
+
+        let svgcom = SvgCom::from_svgcom_str("100 100 2 8\nMC\n10 50 10 -50 90 -50 90 50\n").unwrap();
+        assert_eq!(svgcom.validate().unwrap_err().len(), 1);
+    }
+
+
+    #[test]
+    fn closed_path_round_trip() {
+        let mut svgcom = SvgCom::new(10., 10.);
+        svgcom.commands.move_to((0., 0.));
+        svgcom.commands.line_to((5., 0.));
+        svgcom.commands.line_to((5., 5.));
+        svgcom.commands.close_path();
+
+        let text = svgcom.to_string();
+        let lines = text.lines().collect::<Vec<&str>>();
+
+        let n_cmd = lines[0].split_whitespace().nth(2).unwrap().parse::<usize>().unwrap();
+        assert_eq!(n_cmd, lines[1].len());
+        assert_eq!(lines[2], "0 0 5 0 5 5");
+
+        let parsed = SvgCom::from_svgcom_str(&text).unwrap();
+        assert_eq!(parsed.to_string(), text);
+
+        assert_eq!(svgcom.validate(), Ok(()));
+    }
+
+
+    #[test]
+    fn pressure_round_trip() {
+        let mut svgcom = SvgCom::new(10., 10.);
+        svgcom.commands.move_to((1., 1.));
+        svgcom.commands.line_to((4., 2.));
+        svgcom.pressures = Some(vec![0.7]);
+
+        let text = svgcom.to_string();
+        let parsed = SvgCom::from_svgcom_str(&text).unwrap();
+
+        assert_eq!(parsed.pressures, Some(vec![0.7]));
+        assert_eq!(parsed.to_string(), text);
+    }
+
+
+    #[test]
+    fn malformed_pressure_line_is_garbage() {
+        let parsed = SvgCom::from_svgcom_str("10 10 2 4\nML\n1 1 4 2\nP is for pen").unwrap();
+
+        assert_eq!(parsed.pressures, None);
+        assert_eq!(parsed.commands_count(), 2);
+    }
+
+
+    #[test]
+    fn rounding_modes() {
+        assert_eq!(Rounding::Floor.quantize(1.7, 1.), 1.);
+        assert_eq!(Rounding::Ceil.quantize(1.7, 1.), 2.);
+        assert_eq!(Rounding::Nearest.quantize(1.7, 1.), 2.);
+
+        // 3 * 0.1 would be 0.30000000000000004
+        assert_eq!(Rounding::Nearest.quantize(0.29, 10.), 0.3);
+    }
+
+
+    #[test]
+    fn repair_drops_stray_move_and_fixes_header() {
+        let source = "10 10 9 99\nMMLML\n1 1 2 2 3 3 4 4 5 5";
+        assert!(SvgCom::from_svgcom_str(source).is_err());
+
+        let mut svgcom = SvgCom::from_svgcom_str_lenient(source).unwrap();
+        svgcom.repair();
+
+        let repaired = svgcom.to_string();
+        let lines = repaired.lines().collect::<Vec<&str>>();
+
+        assert_eq!(svgcom.validate(), Ok(()));
+        assert_eq!(lines[..3], ["10 10 4 8", "MLML", "2 2 3 3 4 4 5 5"]);
+        assert!(SvgCom::from_svgcom_str(&repaired).is_ok());
+
+        svgcom.repair();
+        assert_eq!(svgcom.to_string(), repaired);
+    }
+}
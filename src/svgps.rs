@@ -2,8 +2,12 @@ use crate::{
     Error,
     GenerateArgs,
     RenderArgs,
+    ValidateArgs,
+    GcodeArgs,
+    RepairArgs,
 
-    svgcom::*
+    svgcom::*,
+    interrupt,
 };
 
 use std::{
@@ -15,6 +19,7 @@ use std::{
     fs::File,
     path::PathBuf,
     io::prelude::*,
+    io::stderr,
 };
 
 
@@ -47,6 +52,10 @@ pub struct Path {
 
     /// Elemementary path segments: lines and Bezier curves
     pub segments: Vec<kurbo::PathSeg>,
+
+    /// Segment provenance: whether the segment is a line approximating a part of a curve.
+    /// Has the same length as `segments`
+    pub flattened: Vec<bool>,
 }
 
 
@@ -80,29 +89,128 @@ fn open_writable_file(path: &PathBuf) -> Result<File, Error> {
 
 pub fn generate_from_svg(args: GenerateArgs) -> Result<(), Error> {
     let input = read_file(&args.input)?;
-    let mut output = open_writable_file(&args.output)?;
 
     let svg = parse_svg(&input)?;
     let svg_path_nodes = get_svg_path_nodes(&svg, &args);
 
-    let mut paths = svg_path_nodes.iter()
-        .map(|node| Path::from(&node))
-        .collect::<Vec<Path>>();
+    let mut paths = read_paths(&svg_path_nodes, Path::from);
+
+    if args.flatten {
+        let tolerance = get_flatten_tolerance(&args)?;
+
+        for path in paths.iter_mut() {
+            path.flatten(tolerance);
+        }
+    }
+
+    if let Some(degrees) = args.quantize_angle {
+        for path in paths.iter_mut() {
+            path.quantize_angle(degrees.to_radians());
+        }
+    }
+
+    if let Some(tolerance) = args.simplify {
+        let corner_angle = args.simplify_preserve_corners.then(|| args.corner_angle.to_radians());
+
+        for path in paths.iter_mut() {
+            path.simplify(tolerance, corner_angle);
+        }
+    }
 
     if args.autocut {
-        paths = autocut_paths(&paths, args.precision);
+        interrupt::install_handler();
+
+        paths = autocut_paths(&paths, get_flatten_tolerance(&args)?);
+    }
+
+    if let Some(max_error) = args.max_curve_error {
+        for path in paths.iter_mut() {
+            path.refit_curves(max_error);
+        }
+    }
+
+    if args.reflow {
+        paths = reflow_paths(&paths);
+    }
+
+    if let Some(csv_path) = &args.csv {
+        write_csv(csv_path, &paths, get_flatten_tolerance(&args)?)?;
     }
 
-    let mut svgcom = SvgCom::new(svg.size.width(), svg.size.height());
+    if let Some(grid) = &args.split_by_bbox {
+        let view_box = svg.view_box.rect;
+        let view_rect = kurbo::Rect::new(view_box.x(), view_box.y(), view_box.right(), view_box.bottom());
 
-    svgcom.read_from_paths(&paths);
+        return write_tiles(&args, &paths, view_rect, grid[0], grid[1]);
+    }
+
+    write_svgcom(&args.output, &args, &paths, kurbo::Size::new(svg.size.width(), svg.size.height()))
+}
 
-    write!(output, "{}", svgcom.to_string());
+
+fn write_svgcom(path: &PathBuf, args: &GenerateArgs, paths: &[Path], size: kurbo::Size) -> Result<(), Error> {
+    let mut svgcom = SvgCom::new(size.width, size.height);
+
+    if args.pen_pressure {
+        svgcom.pressures = Some(vec![]);
+    }
+
+    svgcom.read_from_paths(paths);
+
+    if let Some(rounding) = args.rounding {
+        svgcom.quantize(get_steps_per_unit(args)?, rounding);
+    }
+
+    let mut output = open_writable_file(path)?;
+
+    write!(output, "{}", svgcom)
+        .map_err(|msg| format!(r#"Cannot write file "{}": {}"#, path.to_string_lossy(), msg))
+}
+
+
+/// Splits the view box into a grid of tiles and writes every tile to OUTPUT-ROW-COL.
+/// The paths are clipped to the tile and their coordinates are made relative to it
+fn write_tiles(args: &GenerateArgs, paths: &[Path], rect: kurbo::Rect, rows: u32, cols: u32) -> Result<(), Error> {
+    if rows == 0 || cols == 0 {
+        return Err("The tile grid must have at least one row and one column".to_string());
+    }
+
+    // Adjacent tiles share the grid lines exactly
+    let grid_x = |col: u32| if col == cols { rect.x1 } else { rect.x0 + col as f64 * rect.width() / cols as f64 };
+    let grid_y = |row: u32| if row == rows { rect.y1 } else { rect.y0 + row as f64 * rect.height() / rows as f64 };
+
+    for row in 0..rows {
+        for col in 0..cols {
+            let tile = kurbo::Rect::new(grid_x(col), grid_y(row), grid_x(col + 1), grid_y(row + 1));
+
+            let translation = kurbo::Affine::translate(-tile.origin().to_vec2());
+
+            // A path on the edge between two tiles goes to the right or lower one
+            let tile_paths = paths.iter()
+                .map(|path| path.clip(tile, col + 1 == cols, row + 1 == rows).transformed(translation))
+                .collect::<Vec<Path>>();
+
+            write_svgcom(&get_tile_file_path(&args.output, row, col), args, &tile_paths, tile.size())?;
+        }
+    }
 
     Ok(())
 }
 
 
+/// `dir/name.ext` -> `dir/name-ROW-COL.ext`
+fn get_tile_file_path(path: &std::path::Path, row: u32, col: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}-{}-{}.{}", stem, row, col, extension.to_string_lossy()),
+        None => format!("{}-{}-{}", stem, row, col),
+    };
+
+    path.with_file_name(file_name)
+}
+
+
 pub fn render_to_svg(args: RenderArgs) -> Result<(), Error> {
     let input = read_file(&args.input)?;
     let mut output = open_writable_file(&args.output)?;
@@ -119,6 +227,147 @@ pub fn render_to_svg(args: RenderArgs) -> Result<(), Error> {
 }
 
 
+pub fn validate_svgcom(args: ValidateArgs) -> Result<(), Error> {
+    let input = read_file(&args.input)?;
+
+    let mut problems = Vec::<Error>::new();
+
+    // A broken file is read leniently to report the rest of its problems too
+    let svgcom = match SvgCom::from_svgcom_str(&input) {
+        Ok(svgcom) => svgcom,
+        Err(message) => {
+            problems.push(message);
+            SvgCom::from_svgcom_str_lenient(&input)?
+        }
+    };
+
+    if let Err(svgcom_problems) = svgcom.validate() {
+        problems.extend(svgcom_problems);
+    }
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            println!("{}", problem);
+        }
+
+        return Err(format!("Found {} problem(s)", problems.len()));
+    }
+
+    println!("No problems found");
+
+    Ok(())
+}
+
+
+pub fn repair_svgcom(args: RepairArgs) -> Result<(), Error> {
+    let input = read_file(&args.input)?;
+
+    let mut svgcom = SvgCom::from_svgcom_str_lenient(&input)?;
+    svgcom.repair();
+
+    let output_path = args.output.as_ref().unwrap_or(&args.input);
+    let mut output = open_writable_file(output_path)?;
+
+    write!(output, "{}", svgcom)
+        .map_err(|msg| format!(r#"Cannot write file "{}": {}"#, output_path.to_string_lossy(), msg))
+}
+
+
+pub fn export_gcode(args: GcodeArgs) -> Result<(), Error> {
+    let input = read_file(&args.input)?;
+    let mut output = open_writable_file(&args.output)?;
+
+    let svgcom = SvgCom::from_svgcom_str(&input)?;
+
+    write!(output, "{}", format_gcode(&svgcom, &args))
+        .map_err(|msg| format!(r#"Cannot write file "{}": {}"#, args.output.to_string_lossy(), msg))
+}
+
+
+/// Moves with the pen raised are rapid (G0), drawing moves are linear (G1).
+/// Curves are flattened into lines.
+/// The Y axis is flipped to point up, with the origin in the bottom left corner of the image.
+/// Subpath pressures (if any) are passed to the pen down command as the S parameter
+fn format_gcode(svgcom: &SvgCom, args: &GcodeArgs) -> String {
+    let height = svgcom.view_size.height as f64;
+
+    let mut gcode = String::from("G90\n");
+    gcode += &format!("{}\n", args.pen_up_cmd);
+
+    let mut pen_down = false;
+    let mut last_point = kurbo::Point::ZERO;
+    let mut subpath_index = 0;
+
+    for cmd in svgcom.commands.iter() {
+        let points = match cmd {
+            kurbo::PathEl::MoveTo(p) => {
+                if pen_down {
+                    gcode += &format!("{}\n", args.pen_up_cmd);
+                    pen_down = false;
+                }
+
+                gcode += &format!("G0 X{} Y{}\n", p.x, height - p.y);
+                last_point = p;
+                subpath_index += 1;
+                continue;
+            }
+
+            kurbo::PathEl::LineTo(p) => vec![p],
+
+            kurbo::PathEl::CurveTo(p1, p2, p3) => {
+                let curve = kurbo::CubicBez::new(last_point, p1, p2, p3);
+                curve_to_points(&curve, args.precision).split_off(1)
+            }
+
+            _ => continue,
+        };
+
+        if !pen_down {
+            let pressure = svgcom.pressures.as_ref()
+                .and_then(|pressures| pressures.get(subpath_index.max(1) - 1));
+
+            match pressure {
+                Some(pressure) => gcode += &format!("{} S{}\n", args.pen_down_cmd, pressure),
+                None => gcode += &format!("{}\n", args.pen_down_cmd),
+            }
+
+            pen_down = true;
+        }
+
+        for p in &points {
+            gcode += &format!("G1 X{} Y{}\n", p.x, height - p.y);
+        }
+
+        last_point = *points.last().unwrap_or(&last_point);
+    }
+
+    if pen_down {
+        gcode += &format!("{}\n", args.pen_up_cmd);
+    }
+
+    gcode
+}
+
+
+/// Tolerance used for flattening curves into lines
+fn get_flatten_tolerance(args: &GenerateArgs) -> Result<f64, Error> {
+    if !args.flatten_tolerance_auto {
+        return Ok(args.precision);
+    }
+
+    Ok(0.5 / get_steps_per_unit(args)?)
+}
+
+
+/// One step per pixel if the plotter resolution is not specified
+fn get_steps_per_unit(args: &GenerateArgs) -> Result<f64, Error> {
+    match args.steps_per_unit.unwrap_or(1.) {
+        steps if steps > 0. => Ok(steps),
+        _ => Err("Steps per unit must be positive".to_string()),
+    }
+}
+
+
 fn parse_svg(input: &str) -> Result<usvg::Tree, Error> {
     usvg::Tree::from_str(&input, &usvg::Options::default())
         .or_else(|err| Err(format!("Cannot parse SVG: {}", err.to_string())))
@@ -140,6 +389,50 @@ fn get_svg_path_nodes(svg: &usvg::Tree, args: &GenerateArgs) -> Vec<SvgPathNode>
 }
 
 
+/// Paths that cannot be read are skipped with a warning
+fn read_paths<F>(nodes: &[SvgPathNode], read: F) -> Vec<Path>
+    where F: Fn(&SvgPathNode) -> Result<Path, Error>
+{
+    nodes.iter()
+        .filter_map(|node| read(node)
+            .map_err(|message| println!("Warning: skipping path: {}", message))
+            .ok())
+        .collect::<Vec<Path>>()
+}
+
+
+fn write_csv(path: &PathBuf, paths: &[Path], precision: f64) -> Result<(), Error> {
+    let mut output = open_writable_file(path)?;
+
+    write!(output, "{}", format_csv(paths, precision))
+        .map_err(|msg| format!(r#"Cannot write file "{}": {}"#, path.to_string_lossy(), msg))
+}
+
+
+/// One row per subpath: index, color, closed flag, segment count, length and bounding box
+fn format_csv(paths: &[Path], precision: f64) -> String {
+    let mut csv = String::from("index,color,closed,segments,length,x0,y0,x1,y1\n");
+
+    let subpaths = paths.iter()
+        .flat_map(|path| path.subpaths());
+
+    for (index, subpath) in subpaths.enumerate() {
+        let bbox = subpath.bounding_box();
+
+        csv += &format!("{},{},{},{},{},{},{},{},{}\n",
+            index,
+            subpath.source.get_color(),
+            subpath.is_closed(),
+            subpath.segments.len(),
+            subpath.length(precision),
+            bbox.x0, bbox.y0, bbox.x1, bbox.y1
+        );
+    }
+
+    csv
+}
+
+
 fn write_svg_start(output: &mut File, args: &RenderArgs, size: &ImageSize) {
     writeln!(output, r#"<?xml version="1.0" standalone="no"?>"#);
 
@@ -228,6 +521,31 @@ impl SvgPathNode {
     }
 
 
+    /// Stroke color (or fill color for unstroked paths) in the `#rrggbb` form
+    pub fn get_color(&self) -> String {
+        let path = self.get_svg_path();
+
+        let paint = path.stroke.as_ref().map(|stroke| &stroke.paint)
+            .or(path.fill.as_ref().map(|fill| &fill.paint));
+
+        match paint {
+            Some(usvg::Paint::Color(color)) =>
+                format!("#{:02x}{:02x}{:02x}", color.red, color.green, color.blue),
+            Some(_) => "paint-server".to_string(),
+            None => "none".to_string(),
+        }
+    }
+
+
+    /// Pen pressure derived from the stroke opacity
+    pub fn get_pressure(&self) -> f64 {
+        let path = self.get_svg_path();
+
+        path.stroke.as_ref()
+            .map_or(1., |stroke| stroke.opacity.get())
+    }
+
+
     pub fn get_points_iter(&self) -> SvgPathPoints {
         SvgPathPoints::from(self)
     }
@@ -251,38 +569,352 @@ impl Path {
     pub fn new(svg_path: &SvgPathNode) -> Self {
         Self {
             source: svg_path.clone(),
-            segments: vec![]
+            segments: vec![],
+            flattened: vec![],
         }
     }
 
 
     /// Copies path segments
-    pub fn from(svg_path: &SvgPathNode) -> Self {
-        Self {
+    ///
+    /// Fails if the path data has fewer points than its commands require
+    pub fn from(svg_path: &SvgPathNode) -> Result<Self, Error> {
+        Self::from_data(svg_path, svg_path.get_commands_iter(), svg_path.get_points_iter())
+    }
+
+
+    /// Builds the path from (possibly inconsistent) commands and points of `svg_path`
+    fn from_data<C, P>(svg_path: &SvgPathNode, commands: C, points: P) -> Result<Self, Error>
+        where C: Iterator<Item = usvg::PathCommand>, P: Iterator<Item = kurbo::Point>
+    {
+        let segments = Self::get_path_segments(commands, points)?;
+
+        Ok(Self {
             source: svg_path.clone(),
-            segments: Self::get_path_segments(&svg_path)
-        }
+            flattened: vec![false; segments.len()],
+            segments,
+        })
+    }
+
+
+    pub fn push_segment(&mut self, segment: kurbo::PathSeg, flattened: bool) {
+        self.segments.push(segment);
+        self.flattened.push(flattened);
     }
 
 
-    fn get_path_segments(svg_node: &SvgPathNode) -> Vec<kurbo::PathSeg> {
+    fn get_path_segments<C, P>(commands: C, mut points: P) -> Result<Vec<kurbo::PathSeg>, Error>
+        where C: Iterator<Item = usvg::PathCommand>, P: Iterator<Item = kurbo::Point>
+    {
         let mut bezpath = kurbo::BezPath::new();
 
-        let mut commands = svg_node.get_commands_iter();
-        let mut points = svg_node.get_points_iter();
-        let mut p = || points.next().unwrap();
+        let mut p = || points.next()
+            .ok_or_else(|| "Path data has fewer points than its commands require".to_string());
         
         for command in commands {
             match command {
-                usvg::PathCommand::MoveTo => bezpath.move_to(p()),
-                usvg::PathCommand::LineTo => bezpath.line_to(p()),
-                usvg::PathCommand::CurveTo => bezpath.curve_to(p(), p(), p()),
+                usvg::PathCommand::MoveTo => bezpath.move_to(p()?),
+                usvg::PathCommand::LineTo => bezpath.line_to(p()?),
+                usvg::PathCommand::CurveTo => bezpath.curve_to(p()?, p()?, p()?),
                 usvg::PathCommand::ClosePath => bezpath.close_path(),
             }
         }
 
-        bezpath.segments()
-            .collect::<Vec<kurbo::PathSeg>>()
+        Ok(bezpath.segments()
+            .collect::<Vec<kurbo::PathSeg>>())
+    }
+
+
+    /// Splits the path into continuous subpaths
+    pub fn subpaths(&self) -> Vec<Path> {
+        use kurbo::ParamCurve;
+
+        let mut subpaths = Vec::<Path>::new();
+
+        for (segment, flattened) in self.segments.iter().zip(&self.flattened) {
+            let continues = subpaths.last()
+                .and_then(|subpath: &Path| subpath.segments.last())
+                .is_some_and(|last| last.end() == segment.start());
+
+            if !continues {
+                subpaths.push(Path::new(&self.source));
+            }
+
+            subpaths.last_mut().unwrap().push_segment(*segment, *flattened);
+        }
+
+        subpaths
+    }
+
+
+    /// Replaces curves with lines
+    pub fn flatten(&mut self, precision: f64) {
+        let mut flat = Path::new(&self.source);
+
+        for (segment, flattened) in self.segments.iter().zip(&self.flattened) {
+            let curve = match segment {
+                kurbo::PathSeg::Line(_) => {
+                    flat.push_segment(*segment, *flattened);
+                    continue;
+                }
+                kurbo::PathSeg::Quad(quad) => quad.raise(),
+                kurbo::PathSeg::Cubic(cubic) => *cubic,
+            };
+
+            let points = curve_to_points(&curve, precision);
+
+            for pair in points.windows(2) {
+                flat.push_segment(kurbo::PathSeg::Line(kurbo::Line::new(pair[0], pair[1])), true);
+            }
+        }
+
+        *self = flat;
+    }
+
+
+    /// Makes lines that are almost horizontal or vertical exactly so by moving their end points.
+    /// The following connected segment is moved along
+    pub fn quantize_angle(&mut self, max_deviation: f64) {
+        use kurbo::ParamCurve;
+        use std::f64::consts::FRAC_PI_2;
+
+        for index in 0..self.segments.len() {
+            let line = match self.segments[index] {
+                kurbo::PathSeg::Line(line) => line,
+                _ => continue,
+            };
+
+            let angle = (line.p1 - line.p0).atan2();
+            let quadrant = (angle / FRAC_PI_2).round();
+
+            if (angle - quadrant * FRAC_PI_2).abs() > max_deviation {
+                continue;
+            }
+
+            let end = if quadrant as i64 % 2 == 0 {
+                kurbo::Point::new(line.p1.x, line.p0.y)
+            } else {
+                kurbo::Point::new(line.p0.x, line.p1.y)
+            };
+
+            self.segments[index] = kurbo::PathSeg::Line(kurbo::Line::new(line.p0, end));
+
+            if let Some(next) = self.segments.get_mut(index + 1) {
+                if next.start() == line.p1 {
+                    set_segment_start(next, end);
+                    continue;
+                }
+            }
+
+            // The line closes its subpath, so the subpath start moves as well
+            let first = self.subpath_start(index);
+
+            if first < index && self.segments[first].start() == line.p1 {
+                set_segment_start(&mut self.segments[first], end);
+            }
+        }
+    }
+
+
+    /// Index of the first segment of the subpath containing the segment
+    fn subpath_start(&self, index: usize) -> usize {
+        use kurbo::ParamCurve;
+
+        (1..=index).rev()
+            .find(|i| self.segments[i - 1].end() != self.segments[*i].start())
+            .unwrap_or(0)
+    }
+
+
+    /// Simplifies runs of connected lines with the Douglas-Peucker algorithm.
+    /// Vertices turning by more than `corner_angle` (if given) are always kept.
+    /// Flattened and original lines are not mixed in a run
+    pub fn simplify(&mut self, tolerance: f64, corner_angle: Option<f64>) {
+        let mut simplified = Path::new(&self.source);
+
+        self.for_each_line_run(&mut simplified, |points, flattened, simplified| {
+            for line in simplify_polyline(points, tolerance, corner_angle) {
+                simplified.push_segment(line, flattened);
+            }
+        });
+
+        *self = simplified;
+    }
+
+
+    /// Fits runs of connected flattened lines with cubic curves deviating by at most `max_error`
+    pub fn refit_curves(&mut self, max_error: f64) {
+        let mut refitted = Path::new(&self.source);
+
+        self.for_each_line_run(&mut refitted, |points, flattened, refitted| {
+            if !flattened || points.len() < 3 {
+                for pair in points.windows(2) {
+                    refitted.push_segment(kurbo::PathSeg::Line(kurbo::Line::new(pair[0], pair[1])), flattened);
+                }
+                return;
+            }
+
+            for curve in fit_curves(points, max_error) {
+                refitted.push_segment(kurbo::PathSeg::Cubic(curve), false);
+            }
+        });
+
+        *self = refitted;
+    }
+
+
+    /// Splits the path into runs of connected lines with the same provenance.
+    /// Each run is passed to `process` as a polyline, other segments are copied to `output`
+    fn for_each_line_run<F>(&self, output: &mut Path, mut process: F)
+        where F: FnMut(&[kurbo::Point], bool, &mut Path)
+    {
+        let mut run = Vec::<kurbo::Point>::new();
+        let mut run_flattened = false;
+
+        for (segment, flattened) in self.segments.iter().zip(&self.flattened) {
+            if let kurbo::PathSeg::Line(line) = segment {
+                if run.last() != Some(&line.p0) || run_flattened != *flattened {
+                    if run.len() > 1 {
+                        process(&run, run_flattened, output);
+                    }
+
+                    run = vec![line.p0];
+                    run_flattened = *flattened;
+                }
+
+                run.push(line.p1);
+                continue;
+            }
+
+            if run.len() > 1 {
+                process(&run, run_flattened, output);
+            }
+
+            run.clear();
+
+            output.push_segment(*segment, *flattened);
+        }
+
+        if run.len() > 1 {
+            process(&run, run_flattened, output);
+        }
+    }
+
+
+    /// Keeps only the parts of the segments lying inside the rect.
+    /// The right and bottom edges are excluded unless closed, so that the rects of a grid do not overlap
+    pub fn clip(&self, rect: kurbo::Rect, closed_right: bool, closed_bottom: bool) -> Path {
+        use kurbo::ParamCurve;
+
+        let edges = [
+            kurbo::Line::new((rect.x0, rect.y0), (rect.x1, rect.y0)),
+            kurbo::Line::new((rect.x1, rect.y0), (rect.x1, rect.y1)),
+            kurbo::Line::new((rect.x1, rect.y1), (rect.x0, rect.y1)),
+            kurbo::Line::new((rect.x0, rect.y1), (rect.x0, rect.y0)),
+        ];
+
+        let mut clipped = Path::new(&self.source);
+
+        for (segment, flattened) in self.segments.iter().zip(&self.flattened) {
+            let mut intersections = edges.iter()
+                .flat_map(|edge| segment.intersect_line(*edge))
+                .collect::<Vec<kurbo::LineIntersection>>();
+
+            intersections.sort_by(|a, b|
+                a.segment_t.partial_cmp(&b.segment_t).unwrap()
+            );
+
+            // Crossing a corner or touching the rect at an end point would produce zero-length pieces
+            intersections.retain(|intersection| intersection.segment_t > 0. && intersection.segment_t < 1.);
+            intersections.dedup_by(|a, b| a.segment_t - b.segment_t < 1e-12);
+
+            for subsegment in cut_segment(segment, &intersections) {
+                let middle = subsegment.eval(0.5);
+
+                let inside = middle.x >= rect.x0 && (middle.x < rect.x1 || closed_right && middle.x == rect.x1)
+                    && middle.y >= rect.y0 && (middle.y < rect.y1 || closed_bottom && middle.y == rect.y1);
+
+                if inside {
+                    let mut subsegment = subsegment;
+
+                    // Cut points are computed with rounding errors and may lie slightly outside
+                    let start = snap_to_rect_edges(subsegment.start(), rect);
+                    let end = snap_to_rect_edges(subsegment.end(), rect);
+
+                    set_segment_start(&mut subsegment, start);
+                    set_segment_end(&mut subsegment, end);
+
+                    clipped.push_segment(subsegment, *flattened);
+                }
+            }
+        }
+
+        clipped
+    }
+
+
+    pub fn transformed(&self, affine: kurbo::Affine) -> Path {
+        Self {
+            source: self.source.clone(),
+            segments: self.segments.iter()
+                .map(|segment| affine * *segment)
+                .collect::<Vec<kurbo::PathSeg>>(),
+            flattened: self.flattened.clone(),
+        }
+    }
+
+
+    pub fn reversed(&self) -> Path {
+        Self {
+            source: self.source.clone(),
+            segments: self.segments.iter()
+                .rev()
+                .map(|segment| segment.reverse())
+                .collect::<Vec<kurbo::PathSeg>>(),
+            flattened: self.flattened.iter()
+                .rev()
+                .copied()
+                .collect::<Vec<bool>>(),
+        }
+    }
+
+
+    /// Returns the line if the path consists of exactly one line
+    fn as_line(&self) -> Option<kurbo::Line> {
+        match self.segments[..] {
+            [kurbo::PathSeg::Line(line)] => Some(line),
+            _ => None,
+        }
+    }
+
+
+    /// Whether the path ends where it starts
+    pub fn is_closed(&self) -> bool {
+        use kurbo::ParamCurve;
+
+        match (self.segments.first(), self.segments.last()) {
+            (Some(first), Some(last)) => first.start() == last.end(),
+            _ => false,
+        }
+    }
+
+
+    pub fn length(&self, precision: f64) -> f64 {
+        use kurbo::ParamCurveArclen;
+
+        self.segments.iter()
+            .map(|segment| segment.arclen(precision))
+            .sum()
+    }
+
+
+    pub fn bounding_box(&self) -> kurbo::Rect {
+        use kurbo::ParamCurveExtrema;
+
+        self.segments.iter()
+            .map(|segment| segment.bounding_box())
+            .reduce(|a, b| a.union(b))
+            .unwrap_or_default()
     }
 
 
@@ -369,19 +1001,234 @@ impl Iterator for SvgPathPoints {
 
         let (x, y) = self.transform.apply(x, y);
 
-        Some(kurbo::Point::new(x, y))
+        Some(kurbo::Point::new(x, y))
+    }
+}
+
+
+impl Iterator for SvgPathCommands {
+    type Item = usvg::PathCommand;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let command = self.path_data.commands().get(self.command_index)?.clone();
+        self.command_index += 1;
+        Some(command)
+    }
+}
+
+
+fn simplify_polyline(
+    points: &[kurbo::Point],
+    tolerance: f64,
+    corner_angle: Option<f64>
+) -> Vec<kurbo::PathSeg> {
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    if let Some(corner_angle) = corner_angle {
+        for index in 1..points.len() - 1 {
+            keep[index] = turn_angle(points[index - 1], points[index], points[index + 1]) > corner_angle;
+        }
+    }
+
+    // Kept vertices split the polyline into independently simplified parts
+    let mut start = 0;
+
+    for end in 1..points.len() {
+        if keep[end] {
+            douglas_peucker(points, start, end, tolerance, &mut keep);
+            start = end;
+        }
+    }
+
+    let kept_points = points.iter()
+        .zip(keep.iter())
+        .filter(|(_, keep)| **keep)
+        .map(|(point, _)| *point)
+        .collect::<Vec<kurbo::Point>>();
+
+    kept_points.windows(2)
+        .map(|pair| kurbo::PathSeg::Line(kurbo::Line::new(pair[0], pair[1])))
+        .collect::<Vec<kurbo::PathSeg>>()
+}
+
+
+fn douglas_peucker(points: &[kurbo::Point], start: usize, end: usize, tolerance: f64, keep: &mut [bool]) {
+    if end <= start + 1 {
+        return;
+    }
+
+    let chord = kurbo::Line::new(points[start], points[end]);
+
+    let (farthest, distance) = (start + 1..end)
+        .map(|index| (index, point_line_distance(points[index], &chord)))
+        .fold((start, 0.), |max, current| if current.1 > max.1 { current } else { max });
+
+    if distance <= tolerance {
+        return;
+    }
+
+    keep[farthest] = true;
+
+    douglas_peucker(points, start, farthest, tolerance, keep);
+    douglas_peucker(points, farthest, end, tolerance, keep);
+}
+
+
+/// Fits a polyline with a chain of cubic curves (Schneider's algorithm without reparameterization).
+/// The polyline is split at the worst fitting point until every curve is within `max_error`
+fn fit_curves(points: &[kurbo::Point], max_error: f64) -> Vec<kurbo::CubicBez> {
+    let start_tangent = points[1] - points[0];
+    let end_tangent = points[points.len() - 2] - points[points.len() - 1];
+
+    let mut curves = Vec::<kurbo::CubicBez>::new();
+    fit_curves_range(points, start_tangent, end_tangent, max_error, &mut curves);
+    curves
+}
+
+
+fn fit_curves_range(
+    points: &[kurbo::Point],
+    start_tangent: kurbo::Vec2,
+    end_tangent: kurbo::Vec2,
+    max_error: f64,
+    curves: &mut Vec<kurbo::CubicBez>
+) {
+    let curve = fit_curve(points, start_tangent, end_tangent);
+    let curve_points = curve_to_points(&curve, max_error / 10.);
+
+    let (worst, error) = (1..points.len() - 1)
+        .map(|index| (index, polyline_point_distance(&curve_points, points[index])))
+        .fold((0, 0.), |max, current| if current.1 > max.1 { current } else { max });
+
+    if error <= max_error || points.len() < 3 {
+        curves.push(curve);
+        return;
+    }
+
+    let split_tangent = points[worst + 1] - points[worst - 1];
+
+    fit_curves_range(&points[..=worst], start_tangent, -split_tangent, max_error, curves);
+    fit_curves_range(&points[worst..], split_tangent, end_tangent, max_error, curves);
+}
+
+
+/// Least squares fit of a single cubic curve with the given end tangents
+fn fit_curve(points: &[kurbo::Point], start_tangent: kurbo::Vec2, end_tangent: kurbo::Vec2) -> kurbo::CubicBez {
+    let first = points[0];
+    let last = points[points.len() - 1];
+
+    let tangent1 = unit_vector(start_tangent, last - first);
+    let tangent2 = unit_vector(end_tangent, first - last);
+
+    let chord_length = (last - first).hypot();
+
+    // Chord length parameterization
+    let mut lengths = vec![0.];
+    for pair in points.windows(2) {
+        lengths.push(lengths.last().unwrap() + (pair[1] - pair[0]).hypot());
+    }
+    let total_length = *lengths.last().unwrap();
+
+    let mut c = [[0.; 2]; 2];
+    let mut x = [0.; 2];
+
+    for (point, length) in points.iter().zip(&lengths) {
+        let t = if total_length > 0. { length / total_length } else { 0. };
+        let mt = 1. - t;
+
+        let a1 = tangent1 * (3. * mt * mt * t);
+        let a2 = tangent2 * (3. * mt * t * t);
+
+        let base = first.to_vec2() * (mt * mt * mt + 3. * mt * mt * t)
+            + last.to_vec2() * (3. * mt * t * t + t * t * t);
+        let rest = point.to_vec2() - base;
+
+        c[0][0] += a1.dot(a1);
+        c[0][1] += a1.dot(a2);
+        c[1][1] += a2.dot(a2);
+        x[0] += a1.dot(rest);
+        x[1] += a2.dot(rest);
+    }
+
+    c[1][0] = c[0][1];
+
+    let det = c[0][0] * c[1][1] - c[0][1] * c[1][0];
+    let mut alpha1 = if det != 0. { (x[0] * c[1][1] - x[1] * c[0][1]) / det } else { 0. };
+    let mut alpha2 = if det != 0. { (c[0][0] * x[1] - c[1][0] * x[0]) / det } else { 0. };
+
+    // Degenerate fits fall back to the heuristic of placing the control points at a third of the chord
+    let epsilon = 1e-6 * chord_length;
+    if !alpha1.is_finite() || !alpha2.is_finite() || alpha1 < epsilon || alpha2 < epsilon {
+        alpha1 = chord_length / 3.;
+        alpha2 = chord_length / 3.;
+    }
+
+    kurbo::CubicBez::new(first, first + tangent1 * alpha1, last + tangent2 * alpha2, last)
+}
+
+
+/// Normalizes the vector, falling back to `fallback` (and then to zero) for zero-length vectors
+fn unit_vector(vector: kurbo::Vec2, fallback: kurbo::Vec2) -> kurbo::Vec2 {
+    [vector, fallback].into_iter()
+        .find(|vector| vector.hypot2() > 0.)
+        .map_or(kurbo::Vec2::ZERO, |vector| vector.normalize())
+}
+
+
+fn polyline_point_distance(polyline: &[kurbo::Point], point: kurbo::Point) -> f64 {
+    polyline.windows(2)
+        .map(|pair| point_line_distance(point, &kurbo::Line::new(pair[0], pair[1])))
+        .fold(f64::INFINITY, f64::min)
+}
+
+
+/// Distance from a point to a line segment
+fn point_line_distance(point: kurbo::Point, line: &kurbo::Line) -> f64 {
+    use kurbo::ParamCurveNearest;
+
+    line.nearest(point, 0.).distance_sq.sqrt()
+}
+
+
+/// Angle (in radians) by which the direction changes at `vertex`
+fn turn_angle(previous: kurbo::Point, vertex: kurbo::Point, next: kurbo::Point) -> f64 {
+    let incoming = vertex - previous;
+    let outgoing = next - vertex;
+
+    incoming.cross(outgoing).atan2(incoming.dot(outgoing)).abs()
+}
+
+
+fn set_segment_start(segment: &mut kurbo::PathSeg, point: kurbo::Point) {
+    match segment {
+        kurbo::PathSeg::Line(line) => line.p0 = point,
+        kurbo::PathSeg::Quad(quad) => quad.p0 = point,
+        kurbo::PathSeg::Cubic(cubic) => cubic.p0 = point,
+    }
+}
+
+
+fn set_segment_end(segment: &mut kurbo::PathSeg, point: kurbo::Point) {
+    match segment {
+        kurbo::PathSeg::Line(line) => line.p1 = point,
+        kurbo::PathSeg::Quad(quad) => quad.p2 = point,
+        kurbo::PathSeg::Cubic(cubic) => cubic.p3 = point,
     }
 }
 
 
-impl Iterator for SvgPathCommands {
-    type Item = usvg::PathCommand;
+fn snap_to_rect_edges(point: kurbo::Point, rect: kurbo::Rect) -> kurbo::Point {
+    let epsilon = 1e-9 * (rect.width() + rect.height());
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let command = self.path_data.commands().get(self.command_index)?.clone();
-        self.command_index += 1;
-        Some(command)
-    }
+    let snap = |value: f64, min: f64, max: f64|
+        if (value - min).abs() <= epsilon { min }
+        else if (value - max).abs() <= epsilon { max }
+        else { value };
+
+    kurbo::Point::new(snap(point.x, rect.x0, rect.x1), snap(point.y, rect.y0, rect.y1))
 }
 
 
@@ -393,6 +1240,44 @@ fn bbox_intersect(bbox1: kurbo::Rect, bbox2: kurbo::Rect) -> bool {
 }
 
 
+fn control_points(segment: &kurbo::PathSeg) -> Vec<kurbo::Point> {
+    match segment {
+        kurbo::PathSeg::Line(line) => vec![line.p0, line.p1],
+        kurbo::PathSeg::Quad(quad) => vec![quad.p0, quad.p1, quad.p2],
+        kurbo::PathSeg::Cubic(cubic) => vec![cubic.p0, cubic.p1, cubic.p2, cubic.p3],
+    }
+}
+
+
+/// Checks whether projections of two point sets onto an axis overlap
+fn projections_overlap(points1: &[kurbo::Point], points2: &[kurbo::Point], axis: kurbo::Vec2) -> bool {
+    let project = |points: &[kurbo::Point]| points.iter()
+        .map(|p| p.to_vec2().dot(axis))
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), x| (min.min(x), max.max(x)));
+
+    let (min1, max1) = project(points1);
+    let (min2, max2) = project(points2);
+
+    min1 <= max2 && min2 <= max1
+}
+
+
+/// Separating axis test on the control polygons, which contain the curves.
+/// The axes are the normals of the segments' chords, which catches thin diagonal segments
+/// that pass the bounding box test
+fn control_hulls_separated(segment1: &kurbo::PathSeg, segment2: &kurbo::PathSeg) -> bool {
+    use kurbo::ParamCurve;
+
+    let points1 = control_points(segment1);
+    let points2 = control_points(segment2);
+
+    [segment1, segment2].iter()
+        .map(|segment| segment.end() - segment.start())
+        .filter(|chord| chord.hypot2() > 0.)
+        .any(|chord| !projections_overlap(&points1, &points2, kurbo::Vec2::new(-chord.y, chord.x)))
+}
+
+
 fn get_segment_intersection(
     intersected: &kurbo::PathSeg,
     intersecting: &kurbo::PathSeg,
@@ -457,6 +1342,12 @@ fn get_curve_intersection(
         return vec![]
     }
 
+    let intersecting_segment = kurbo::PathSeg::Cubic(*intersecting);
+
+    if control_hulls_separated(intersected, &intersecting_segment) {
+        return vec![]
+    }
+
     let points = curve_to_points(&intersecting, precision);
     let line_starts = points[..points.len()-1].iter();
     let line_ends = points[1..].iter();
@@ -475,9 +1366,19 @@ fn get_curve_intersection(
 }
 
 
+#[cfg(test)]
+thread_local! {
+    /// Number of [curve_to_points] calls
+    static FLATTEN_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+
 fn curve_to_points(curve: &kurbo::CubicBez, precision: f64) -> Vec<kurbo::Point> {
     use kurbo::Shape;
 
+    #[cfg(test)]
+    FLATTEN_CALLS.with(|calls| calls.set(calls.get() + 1));
+
     let mut points = Vec::<kurbo::Point>::new();
 
     curve
@@ -490,6 +1391,15 @@ fn curve_to_points(curve: &kurbo::CubicBez, precision: f64) -> Vec<kurbo::Point>
             }
         );
 
+    // The end points must be exact so that the adjacent segments stay connected
+    if let Some(first) = points.first_mut() {
+        *first = curve.p0;
+    }
+
+    if let Some(last) = points.last_mut() {
+        *last = curve.p3;
+    }
+
     points
 }
 
@@ -528,12 +1438,19 @@ fn get_path_intersections(
 ) {
 
     for (segment_index, intersected_segment) in intersected.segments.iter().enumerate() {
-        for intersecting_segment in intersecting.segments.iter() {
+        for (intersecting_index, intersecting_segment) in intersecting.segments.iter().enumerate() {
             let segment_intersections = get_segment_intersection(
                 intersected_segment,
                 intersecting_segment,
                 precision
-            );
+            )
+                .into_iter()
+                .filter(|intersection| !is_junction(
+                    (intersected, segment_index),
+                    (intersecting, intersecting_index),
+                    intersection
+                ))
+                .collect::<Vec<kurbo::LineIntersection>>();
 
             if segment_intersections.is_empty() {
                 continue;
@@ -550,6 +1467,89 @@ fn get_path_intersections(
 }
 
 
+/// Distance under which an intersection is considered to be at a segment end point,
+/// also the angle under which two directions at a vertex are considered equal
+const JUNCTION_EPSILON: f64 = 1e-9;
+
+
+/// Checks whether the intersection is a vertex shared by both paths where they touch without crossing.
+/// Each path is given with the index of the intersected segment
+fn is_junction(
+    intersected: (&Path, usize),
+    intersecting: (&Path, usize),
+    intersection: &kurbo::LineIntersection
+) -> bool {
+    use kurbo::ParamCurve;
+
+    let point = intersected.0.segments[intersected.1].eval(intersection.segment_t);
+
+    match (vertex_directions(intersected, point), vertex_directions(intersecting, point)) {
+        (Some(directions1), Some(directions2)) => !directions_cross(&directions1, &directions2),
+        _ => false,
+    }
+}
+
+
+/// Directions in which the path leaves the vertex at the given end point of the segment.
+/// Returns None if the point is not an end point of the segment
+fn vertex_directions((path, index): (&Path, usize), point: kurbo::Point) -> Option<Vec<kurbo::Vec2>> {
+    use kurbo::ParamCurve;
+
+    let segment = &path.segments[index];
+    let last = path.segments.len() - 1;
+
+    let (previous, next) = if point.distance(segment.start()) <= JUNCTION_EPSILON {
+        let previous = if index > 0 { Some(index - 1) } else if path.is_closed() { Some(last) } else { None };
+        (previous, Some(index))
+    } else if point.distance(segment.end()) <= JUNCTION_EPSILON {
+        let next = if index < last { Some(index + 1) } else if path.is_closed() { Some(0) } else { None };
+        (Some(index), next)
+    } else {
+        return None;
+    };
+
+    let mut directions = Vec::<kurbo::Vec2>::new();
+
+    if let Some(previous) = previous.filter(|previous| path.segments[*previous].end().distance(point) <= JUNCTION_EPSILON) {
+        let points = control_points(&path.segments[previous]);
+        directions.extend(points.iter().rev().skip(1).map(|p| *p - point).find(|v| v.hypot() > JUNCTION_EPSILON));
+    }
+
+    if let Some(next) = next.filter(|next| path.segments[*next].start().distance(point) <= JUNCTION_EPSILON) {
+        let points = control_points(&path.segments[next]);
+        directions.extend(points.iter().skip(1).map(|p| *p - point).find(|v| v.hypot() > JUNCTION_EPSILON));
+    }
+
+    Some(directions)
+}
+
+
+/// Checks whether the second path passes from one side of the first path to the other
+/// at a common vertex. Both are given by the directions in which they leave the vertex.
+/// A path that ends at the vertex or runs along the other one does not cross it
+fn directions_cross(directions1: &[kurbo::Vec2], directions2: &[kurbo::Vec2]) -> bool {
+    use std::f64::consts::TAU;
+
+    if directions1.len() != 2 || directions2.len() != 2 {
+        return false;
+    }
+
+    // Counterclockwise angle from the first direction of the first path
+    let angle = |v: kurbo::Vec2| (v.atan2() - directions1[0].atan2()).rem_euclid(TAU);
+    let sector = angle(directions1[1]);
+
+    let sides = directions2.iter()
+        .map(|v| angle(*v))
+        .map(|a| {
+            let along = |b: f64| (a - b).abs().min(TAU - (a - b).abs()) <= JUNCTION_EPSILON;
+            if along(0.) || along(sector) { None } else { Some(a < sector) }
+        })
+        .collect::<Option<Vec<bool>>>();
+
+    matches!(sides.as_deref(), Some([side1, side2]) if side1 != side2)
+}
+
+
 fn cut_path(path: &Path, intersections: &PathIntersections) -> Vec<Path> {
 
     let mut subpaths = Vec::<Path>::new();
@@ -559,9 +1559,11 @@ fn cut_path(path: &Path, intersections: &PathIntersections) -> Vec<Path> {
     subpaths.push(new_path());
 
     for (index, segment) in path.segments.iter().enumerate() {
+        let flattened = path.flattened[index];
+
         if !intersections.contains_key(&index) {
             // A non-intersected segment is added to the last path as is
-            subpaths.last_mut().unwrap().segments.push(segment.clone());
+            subpaths.last_mut().unwrap().push_segment(segment.clone(), flattened);
         } else {
             // A segment intersection ends the old path and starts a new one.
             // The subsegment before the intersection goes to the old path.
@@ -570,14 +1572,14 @@ fn cut_path(path: &Path, intersections: &PathIntersections) -> Vec<Path> {
             let mut subsegments = cut_segment(segment, &intersections[&index]).into_iter();
 
             if let Some(segment) = subsegments.next() {
-                subpaths.last_mut().unwrap().segments.push(segment.clone());
+                subpaths.last_mut().unwrap().push_segment(segment.clone(), flattened);
             }
 
             // A segment may be intersected multiple times.
             // In that case, every intersection starting with the second one begins a new path
             for segment in subsegments {
                 subpaths.push(new_path());
-                subpaths.last_mut().unwrap().segments.push(segment.clone());
+                subpaths.last_mut().unwrap().push_segment(segment.clone(), flattened);
             }
         }
     }
@@ -588,10 +1590,15 @@ fn cut_path(path: &Path, intersections: &PathIntersections) -> Vec<Path> {
 
 
 
+/// When interrupted, only the paths processed so far are returned
 fn autocut_paths(paths: &Vec<Path>, precision: f64) -> Vec<Path> {
     let intersections = intersect_paths(paths, precision);
 
-    let cut_paths = cut_paths(paths, intersections);
+    if intersections.len() < paths.len() {
+        eprintln!("Interrupted: keeping {} of {} paths", intersections.len(), paths.len());
+    }
+
+    let cut_paths = cut_paths(&paths[..intersections.len()], intersections);
 
     let covering_shapes = create_covering_shapes(paths);
 
@@ -600,18 +1607,32 @@ fn autocut_paths(paths: &Vec<Path>, precision: f64) -> Vec<Path> {
 
 
 
-/// result[i] contains intersections of paths[i]
+/// result[i] contains intersections of paths[i].
+///
+/// Stops early when interrupted, so the result may be shorter than `paths`.
+/// The intersections of the processed paths are complete
 fn intersect_paths(paths: &Vec<Path>, precision: f64) -> Vec::<PathIntersections> {
     let mut path_intersections = Vec::<PathIntersections>::with_capacity(paths.len());
 
     for (index, intersected_path) in paths.iter().enumerate() {
-        path_intersections.push(PathIntersections::new());
+        if interrupt::is_interrupted() {
+            break;
+        }
+
+        eprint!("\rAutocut: {}/{} paths", index + 1, paths.len());
+        stderr().flush();
+
+        let mut intersections = PathIntersections::new();
 
         for intersecting_path in &paths[index+1..] {
-            get_path_intersections(intersected_path, intersecting_path, path_intersections.last_mut().unwrap(), precision);
+            get_path_intersections(intersected_path, intersecting_path, &mut intersections, precision);
         }
+
+        path_intersections.push(intersections);
     }
 
+    eprintln!();
+
     for intersections in path_intersections.iter_mut() {
         for (segment_index, segment_intersections) in intersections.iter_mut() {
             segment_intersections.sort_by(|a, b|
@@ -624,7 +1645,7 @@ fn intersect_paths(paths: &Vec<Path>, precision: f64) -> Vec::<PathIntersections
 }
 
 
-fn cut_paths(paths: &Vec<Path>, intersections: Vec<PathIntersections>) -> Vec<Path> {
+fn cut_paths(paths: &[Path], intersections: Vec<PathIntersections>) -> Vec<Path> {
     let mut cut_paths = Vec::<Path>::new();
 
     for (index, path) in paths.iter().enumerate() {
@@ -681,4 +1702,489 @@ fn remove_covered_paths(paths: Vec<Path>, covering_shapes: &Vec<Option<CoveringS
         })
         .map(|(index, shape)| shape)
         .collect::<Vec::<Path>>()
-}
\ No newline at end of file
+}
+
+
+/// Orients consecutive parallel lines in alternating directions.
+/// Consecutive collinear lines (e.g. a row split by autocut) form a single row
+/// and are reversed together
+fn reflow_paths(paths: &[Path]) -> Vec<Path> {
+    let mut rows = Vec::<Vec<Path>>::new();
+
+    for subpath in paths.iter().flat_map(|path| path.subpaths()) {
+        let previous_line = rows.last()
+            .and_then(|row| row.last())
+            .and_then(|path| path.as_line());
+
+        match (previous_line, subpath.as_line()) {
+            (Some(previous), Some(line)) if lines_collinear(&previous, &line) =>
+                rows.last_mut().unwrap().push(subpath),
+
+            _ => rows.push(vec![subpath]),
+        }
+    }
+
+    let mut reflown = Vec::<Path>::new();
+
+    for row in rows {
+        let previous_line = reflown.last().and_then(|path: &Path| path.as_line());
+
+        match (previous_line, row[0].as_line()) {
+            (Some(previous), Some(line)) if lines_parallel(&previous, &line) => {
+                let previous_direction = previous.p1 - previous.p0;
+                let direction = line.p1 - line.p0;
+
+                if previous_direction.dot(direction) > 0. {
+                    reflown.extend(row.iter().rev().map(|path| path.reversed()));
+                } else {
+                    reflown.extend(row);
+                }
+            }
+
+            _ => reflown.extend(row),
+        }
+    }
+
+    reflown
+}
+
+
+fn lines_collinear(line1: &kurbo::Line, line2: &kurbo::Line) -> bool {
+    let direction1 = line1.p1 - line1.p0;
+    let offset = line2.p0 - line1.p0;
+
+    lines_parallel(line1, line2) && offset.cross(direction1).abs() <= 1e-9 * direction1.hypot2()
+}
+
+
+fn lines_parallel(line1: &kurbo::Line, line2: &kurbo::Line) -> bool {
+    let direction1 = line1.p1 - line1.p0;
+    let direction2 = line2.p1 - line2.p0;
+
+    let cross = direction1.cross(direction2);
+
+    cross.abs() <= 1e-9 * direction1.hypot() * direction2.hypot()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{Args, ArgCommand};
+
+
+    fn generate_args(options: &[&str]) -> GenerateArgs {
+        use clap::Parser;
+
+        let args = ["svgps", "generate", "input.svg", "output.svgcom"].iter()
+            .chain(options);
+
+        match Args::parse_from(args).command {
+            ArgCommand::Generate(args) => args,
+            _ => unreachable!(),
+        }
+    }
+
+
+    fn parse_paths(svg: &str) -> Vec<Path> {
+        let svg = parse_svg(svg).unwrap();
+
+        get_svg_path_nodes(&svg, &generate_args(&[])).iter()
+            .map(|node| Path::from(node).unwrap())
+            .collect::<Vec<Path>>()
+    }
+
+
+    fn svg_with_paths(paths: &str) -> String {
+        format!(r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 100">{}</svg>"#, paths)
+    }
+
+
+    #[test]
+    fn flatten_tolerance_is_half_a_step() {
+        let args = generate_args(&["--flatten-tolerance-auto", "--steps-per-unit", "4"]);
+        assert_eq!(get_flatten_tolerance(&args), Ok(0.5 / 4.));
+
+        let args = generate_args(&["--precision", "0.1", "--steps-per-unit", "4"]);
+        assert_eq!(get_flatten_tolerance(&args), Ok(0.1));
+    }
+
+
+    #[test]
+    fn steps_per_unit_must_be_positive() {
+        assert_eq!(get_steps_per_unit(&generate_args(&[])), Ok(1.));
+
+        for steps in ["--steps-per-unit=0", "--steps-per-unit=-2"] {
+            let args = generate_args(&["--rounding", "floor", steps]);
+            assert!(get_steps_per_unit(&args).is_err());
+        }
+    }
+
+
+    #[test]
+    fn truncated_path_data_is_an_error() {
+        let paths = parse_paths(&svg_with_paths(r#"<path stroke="black" d="M 0 0 L 10 10 L 20 0"/>"#));
+        let node = &paths[0].source;
+
+        let truncated_points = node.get_points_iter().take(2);
+
+        assert!(Path::from_data(node, node.get_commands_iter(), truncated_points).is_err());
+        assert!(Path::from(node).is_ok());
+    }
+
+
+    #[test]
+    fn truncated_path_is_skipped() {
+        let nodes = parse_paths(&svg_with_paths(concat!(
+            r#"<path stroke="black" d="M 0 0 L 10 10 L 20 0"/>"#,
+            r#"<path stroke="black" d="M 30 30 L 40 40"/>"#,
+        ))).iter()
+            .map(|path| path.source.clone())
+            .collect::<Vec<SvgPathNode>>();
+
+        // The points of the first path are cut short
+        let paths = read_paths(&nodes, |node| if *node == nodes[0] {
+            Path::from_data(node, node.get_commands_iter(), node.get_points_iter().take(2))
+        } else {
+            Path::from(node)
+        });
+
+        let mut svgcom = SvgCom::new(100., 100.);
+        svgcom.read_from_paths(&paths);
+
+        assert_eq!(svgcom.to_string().lines().nth(2), Some("30 30 40 40"));
+    }
+
+
+    #[test]
+    fn csv_has_row_per_subpath() {
+        let paths = parse_paths(&svg_with_paths(
+            r#"<path stroke="black" d="M 0 0 L 30 40 M 50 50 L 60 50 L 60 60 Z"/>
+               <path stroke="black" d="M 10 10 L 10 30"/>"#
+        ));
+
+        let csv = format_csv(&paths, 0.1);
+        let rows = csv.lines().skip(1).collect::<Vec<&str>>();
+
+        assert_eq!(rows.len(), 3);
+
+        let lengths = rows.iter()
+            .map(|row| row.split(',').nth(4).unwrap().parse::<f64>().unwrap())
+            .collect::<Vec<f64>>();
+
+        assert_eq!(lengths, vec![50., 20. + 200f64.sqrt(), 20.]);
+        assert!(rows[1].contains(",true,"));
+    }
+
+
+    #[test]
+    fn reflow_reverses_alternate_hatch_lines() {
+        let paths = parse_paths(&svg_with_paths(
+            r#"<path stroke="black" d="M 0 0 L 100 0 M 0 10 L 100 10 M 0 20 L 100 20 M 0 30 L 100 30"/>"#
+        ));
+
+        let lines = reflow_paths(&paths).iter()
+            .map(|path| path.as_line().unwrap())
+            .collect::<Vec<kurbo::Line>>();
+
+        assert_eq!(lines.len(), 4);
+
+        for (index, line) in lines.iter().enumerate() {
+            let expected_start_x = if index % 2 == 0 { 0. } else { 100. };
+            assert_eq!(line.p0.x, expected_start_x);
+        }
+
+        for pair in lines.windows(2) {
+            assert_eq!(pair[0].p1.x, pair[1].p0.x);
+        }
+    }
+
+
+    #[test]
+    fn reflow_keeps_split_rows_together() {
+        let paths = parse_paths(&svg_with_paths(
+            r#"<path stroke="black" d="M 0 10 L 40 10 M 60 10 L 100 10 M 0 20 L 40 20 M 60 20 L 100 20"/>"#
+        ));
+
+        let lines = reflow_paths(&paths).iter()
+            .map(|path| path.as_line().unwrap())
+            .map(|line| (line.p0.x, line.p1.x))
+            .collect::<Vec<(f64, f64)>>();
+
+        assert_eq!(lines, vec![(0., 40.), (60., 100.), (100., 60.), (40., 0.)]);
+    }
+
+
+    #[test]
+    fn quantize_angle_makes_line_horizontal() {
+        use kurbo::ParamCurve;
+
+        let end_y = 100. * 0.5f64.to_radians().tan();
+
+        let mut path = parse_paths(&svg_with_paths(&format!(
+            r#"<path stroke="black" d="M 0 0 L 100 {} L 100 50"/>"#, end_y
+        ))).remove(0);
+
+        path.quantize_angle(1f64.to_radians());
+
+        assert_eq!(path.segments[0], kurbo::PathSeg::Line(kurbo::Line::new((0., 0.), (100., 0.))));
+        assert_eq!(path.segments[1], kurbo::PathSeg::Line(kurbo::Line::new((100., 0.), (100., 50.))));
+
+        // The closing line is snapped to vertical together with the start of the subpath
+        let mut path = parse_paths(&svg_with_paths(
+            r#"<path stroke="black" d="M 50 50 L 60 50 M 10 10 L 90 10 L 90 90 L 10.5 90 Z"/>"#
+        )).remove(0);
+
+        path.quantize_angle(1f64.to_radians());
+
+        assert_eq!(path.segments[1].start(), kurbo::Point::new(10.5, 10.));
+        assert_eq!(path.segments[4].end(), kurbo::Point::new(10.5, 10.));
+        assert_eq!(path.segments[0], kurbo::PathSeg::Line(kurbo::Line::new((50., 50.), (60., 50.))));
+        assert!(path.subpaths()[1].is_closed());
+    }
+
+
+    #[test]
+    fn gcode_uses_custom_pen_commands() {
+        use clap::Parser;
+
+        let args = match Args::parse_from(["svgps", "gcode", "in.svgcom", "out.gcode",
+            "--pen-down-cmd", "M3", "--pen-up-cmd", "M5"]).command
+        {
+            ArgCommand::Gcode(args) => args,
+            _ => unreachable!(),
+        };
+
+        let svgcom = SvgCom::from_svgcom_str("100 100 4 8\nMLML\n0 0 10 0 20 20 30 30\n").unwrap();
+
+        let gcode = format_gcode(&svgcom, &args);
+
+        assert_eq!(gcode, "G90\nM5\n\
+            G0 X0 Y100\nM3\nG1 X10 Y100\nM5\n\
+            G0 X20 Y80\nM3\nG1 X30 Y70\nM5\n");
+    }
+
+
+    #[test]
+    fn separated_diagonal_curves_are_not_flattened() {
+        use kurbo::Shape;
+
+        let diagonal = |dx: f64, dy: f64| kurbo::CubicBez::new(
+            (dx, dy), (30. + dx, 30. + dy), (70. + dx, 70. + dy), (100. + dx, 100. + dy)
+        );
+
+        let intersected = kurbo::PathSeg::Cubic(diagonal(0., 0.));
+        let parallel = diagonal(10., -10.);
+        let crossing = kurbo::CubicBez::new((0., 100.), (30., 70.), (70., 30.), (100., 0.));
+
+        assert!(bbox_intersect(intersected.bounding_box(), parallel.bounding_box()));
+
+        FLATTEN_CALLS.with(|calls| calls.set(0));
+        assert!(get_curve_intersection(&intersected, &parallel, 0.25).is_empty());
+        assert_eq!(FLATTEN_CALLS.with(|calls| calls.get()), 0);
+
+        assert!(!get_curve_intersection(&intersected, &crossing, 0.25).is_empty());
+        assert_eq!(FLATTEN_CALLS.with(|calls| calls.get()), 1);
+    }
+
+
+    #[test]
+    fn simplify_preserves_corners() {
+        use kurbo::ParamCurve;
+
+        // A right angle followed by a gentle arc turning by 9 degrees per vertex
+        let arc = (1..=10)
+            .map(|i| (i as f64 * 9.).to_radians())
+            .map(|angle| format!("L {} {}", 100. - 50. * angle.cos(), 50. + 50. * angle.sin()))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        let path = parse_paths(&svg_with_paths(&format!(
+            r#"<path stroke="black" d="M 0 0 L 50 0 L 50 50 {}"/>"#, arc
+        ))).remove(0);
+
+        let vertices = |path: &Path| path.segments.iter()
+            .map(|segment| segment.end())
+            .collect::<Vec<kurbo::Point>>();
+
+        let mut simplified = path.clone();
+        simplified.simplify(100., Some(45f64.to_radians()));
+        assert_eq!(vertices(&simplified), vec![kurbo::Point::new(50., 0.), kurbo::Point::new(100., 100.)]);
+
+        let mut simplified = path.clone();
+        simplified.simplify(100., None);
+        assert_eq!(vertices(&simplified), vec![kurbo::Point::new(100., 100.)]);
+    }
+
+
+    #[test]
+    fn flattening_keeps_exact_end_points() {
+        let curve = kurbo::CubicBez::new((0.1, 0.7), (13.37, -4.2), (29.9, 41.3), (31.7, 0.3));
+
+        let points = curve_to_points(&curve, 0.01);
+        assert_eq!(points.first(), Some(&curve.p0));
+        assert_eq!(points.last(), Some(&curve.p3));
+
+        let mut path = parse_paths(&svg_with_paths(r#"<path stroke="black" d="M 0 0 L 1 1"/>"#)).remove(0);
+        path.segments = vec![kurbo::PathSeg::Cubic(curve), kurbo::PathSeg::Line(kurbo::Line::new(curve.p3, (50., 50.)))];
+        path.flattened = vec![false, false];
+
+        path.flatten(0.01);
+
+        // ParamCurve::end evaluates the segment, so the stored points are compared directly
+        let lines = path.segments.iter()
+            .map(|segment| match segment {
+                kurbo::PathSeg::Line(line) => *line,
+                _ => panic!("curve is not flattened"),
+            })
+            .collect::<Vec<kurbo::Line>>();
+
+        assert_eq!(lines[0].p0, curve.p0);
+        assert_eq!(lines[lines.len() - 2].p1, curve.p3);
+        assert!(lines.windows(2).all(|pair| pair[0].p1 == pair[1].p0));
+    }
+
+
+    #[test]
+    fn clipped_curve_is_refitted() {
+        use kurbo::ParamCurve;
+
+        let curve = kurbo::CubicBez::new((0., 0.), (30., 80.), (70., -40.), (100., 20.));
+        let clip_rect = kurbo::Rect::new(0., -50., 60., 100.);
+        let (flatten_precision, max_error) = (0.01, 0.5);
+
+        let mut path = parse_paths(&svg_with_paths(r#"<path stroke="black" d="M 0 0 L 1 1"/>"#)).remove(0);
+        path.segments = vec![kurbo::PathSeg::Cubic(curve)];
+        path.flattened = vec![false];
+        path.flatten(flatten_precision);
+
+        let mut path = path.clip(clip_rect, true, true);
+        path.refit_curves(max_error);
+
+        let refitted = path.segments.iter()
+            .flat_map(|segment| match segment {
+                kurbo::PathSeg::Cubic(cubic) => curve_to_points(cubic, 0.001),
+                _ => panic!("clipped pieces of the flattened curve are not refitted"),
+            })
+            .collect::<Vec<kurbo::Point>>();
+
+        assert!(path.segments.len() < 10);
+        assert_eq!(refitted.first(), Some(&curve.p0));
+        assert!((refitted.last().unwrap().x - clip_rect.x1).abs() < 1e-9);
+
+        let clipped_curve = (0..=1000)
+            .map(|i| curve.eval(i as f64 / 1000.))
+            .filter(|point| clip_rect.contains(*point));
+
+        for point in clipped_curve {
+            assert!(polyline_point_distance(&refitted, point) <= max_error + flatten_precision + 0.001);
+        }
+    }
+
+
+    #[test]
+    fn autocut_ignores_touching_vertex() {
+        // Both paths turn back at (10, 10) without crossing each other
+        let paths = parse_paths(&svg_with_paths(concat!(
+            r#"<path stroke="black" fill="none" d="M 0 0 L 10 10 L 20 0"/>"#,
+            r#"<path stroke="black" fill="none" d="M 0 20 L 10 10 L 20 20"/>"#,
+        )));
+
+        assert_eq!(autocut_paths(&paths, 0.25).len(), 2);
+
+        // The second path ends at the vertex of the first one
+        let paths = parse_paths(&svg_with_paths(concat!(
+            r#"<path stroke="black" fill="none" d="M 0 0 L 10 10 L 20 0"/>"#,
+            r#"<path stroke="black" fill="none" d="M 10 10 L 10 30"/>"#,
+        )));
+
+        assert_eq!(autocut_paths(&paths, 0.25).len(), 2);
+    }
+
+
+    #[test]
+    fn autocut_cuts_crossing_at_shared_vertex() {
+        // The second path passes through (10, 10) from above the first path to below it
+        let paths = parse_paths(&svg_with_paths(concat!(
+            r#"<path stroke="black" fill="none" d="M 0 0 L 10 10 L 20 0"/>"#,
+            r#"<path stroke="black" fill="none" d="M 0 20 L 10 10 L 10 0"/>"#,
+        )));
+
+        assert!(autocut_paths(&paths, 0.25).len() > 2);
+    }
+
+
+    #[test]
+    fn interrupted_autocut_writes_processed_paths() {
+        let dir = std::env::temp_dir().join(format!("svgps-interrupt-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let input = dir.join("input.svg");
+        let output = dir.join("output.svgcom");
+
+        std::fs::write(&input, svg_with_paths(concat!(
+            r#"<path stroke="black" fill="none" d="M 0 0 L 10 0"/>"#,
+            r#"<path stroke="black" fill="none" d="M 0 10 L 10 10"/>"#,
+            r#"<path stroke="black" fill="none" d="M 0 20 L 10 20"/>"#,
+        ))).unwrap();
+
+        let mut args = generate_args(&["--autocut"]);
+        args.input = input;
+        args.output = output.clone();
+
+        // Ctrl-C while the second path is processed
+        interrupt::simulate_interrupt(Some(1));
+        let result = generate_from_svg(args);
+        interrupt::simulate_interrupt(None);
+
+        let svgcom = SvgCom::from_svgcom_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(svgcom.to_string().lines().nth(1), Some("ML"));
+    }
+
+
+    #[test]
+    fn tile_edge_line_goes_to_one_tile() {
+        let path = parse_paths(&svg_with_paths(r#"<path stroke="black" d="M 10 50 L 40 50 M 0 100 L 20 100"/>"#)).remove(0);
+
+        let upper = kurbo::Rect::new(0., 0., 50., 50.);
+        let lower = kurbo::Rect::new(0., 50., 50., 100.);
+
+        assert!(path.clip(upper, false, false).segments.is_empty());
+        assert_eq!(path.clip(lower, false, true).segments, path.segments);
+    }
+
+
+    #[test]
+    fn tiles_have_relative_coordinates() {
+        let dir = std::env::temp_dir().join(format!("svgps-tiles-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let input = dir.join("input.svg");
+        std::fs::write(&input, svg_with_paths(r#"<path stroke="black" fill="none" d="M 20 70 L 80 70"/>"#)).unwrap();
+
+        let mut args = generate_args(&["--split-by-bbox", "2", "2"]);
+        args.input = input;
+        args.output = dir.join("output.svgcom");
+
+        let result = generate_from_svg(args);
+
+        let read_tile = |row, col| std::fs::read_to_string(dir.join(format!("output-{}-{}.svgcom", row, col))).unwrap();
+        let tiles = [read_tile(0, 0), read_tile(0, 1), read_tile(1, 0), read_tile(1, 1)];
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let header_and_coords = |tile: &str| {
+            let lines = tile.lines().collect::<Vec<&str>>();
+            (lines[0].to_string(), lines[2].to_string())
+        };
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(header_and_coords(&tiles[0]), ("50 50 0 0".to_string(), "".to_string()));
+        assert_eq!(header_and_coords(&tiles[1]), ("50 50 0 0".to_string(), "".to_string()));
+        assert_eq!(header_and_coords(&tiles[2]), ("50 50 2 4".to_string(), "20 20 50 20".to_string()));
+        assert_eq!(header_and_coords(&tiles[3]), ("50 50 2 4".to_string(), "0 20 30 20".to_string()));
+    }
+}